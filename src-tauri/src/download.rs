@@ -1,8 +1,9 @@
 use std::{
+  collections::HashMap,
   fs::{File, OpenOptions},
-  io::{BufReader, BufWriter, Seek, SeekFrom, Write},
+  io::{BufReader, BufWriter, Read, Seek, SeekFrom, Write},
   path::Path,
-  sync::Mutex,
+  sync::{Arc, Mutex},
   time::Instant,
 };
 use anyhow::Result;
@@ -10,13 +11,41 @@ use futures::StreamExt;
 use lazy_static::lazy_static;
 use reqwest_retry::{policies::ExponentialBackoff, RetryTransientMiddleware};
 use serde::{Deserialize, Serialize};
-use tokio::{select, task};
+use sha2::{Digest, Sha256};
+use tokio::{select, sync::Semaphore, task};
 use tokio_util::sync::CancellationToken;
 use tauri::{command, AppHandle};
 
+// 每个 host 允许的最大在途请求数缺省值，可由命令参数覆盖，避免触发限流 / 反 DDoS
+const DEFAULT_MAX_REQUESTS_PER_HOST: usize = 8;
+
 lazy_static! {
-  static ref TOKENS: Mutex<std::collections::HashMap<String, CancellationToken>> =
-    Mutex::new(std::collections::HashMap::new());
+  static ref TOKENS: Mutex<HashMap<String, CancellationToken>> =
+    Mutex::new(HashMap::new());
+  // 按 host 复用的并发闸门，跨所有 DownloadState 全局生效；记录当前许可上限以便调整
+  static ref HOST_SEMAPHORES: Mutex<HashMap<String, (Arc<Semaphore>, usize)>> =
+    Mutex::new(HashMap::new());
+}
+
+/// 获取（或惰性创建）某个 host 的并发信号量，并按最新的 `max` 调整许可数，
+/// 而非只认首次创建时的值：调大立即生效；调小只回收当前空闲的许可，
+/// 仍被占用的许可不会收回，超出部分留待之后的调用再回收
+fn host_semaphore(host: &str, max: usize) -> Arc<Semaphore> {
+  let mut map = HOST_SEMAPHORES.lock().unwrap();
+  let entry = map
+    .entry(host.to_string())
+    .or_insert_with(|| (Arc::new(Semaphore::new(max)), max));
+  let (sem, current) = entry;
+  // 调大则补发许可，调小则尽量回收，使在途上限贴合最新配置
+  if max > *current {
+    sem.add_permits(max - *current);
+    *current = max;
+  } else if max < *current {
+    let removed = (*current - max).min(sem.available_permits());
+    sem.forget_permits(removed);
+    *current -= removed;
+  }
+  sem.clone()
 }
 
 // 单个分片状态
@@ -35,6 +64,8 @@ struct DownloadState {
   output: String,
   total_size: u64,
   concurrency: usize,
+  // 服务器是否支持 Range 请求；为 false 时走单流顺序下载
+  use_ranges: bool,
   segments: Vec<SegmentState>,
 }
 
@@ -58,6 +89,47 @@ impl DownloadState {
   }
 }
 
+// 下载产物的处理方式：Raw 直接落盘，其余为边下边解压解包
+#[derive(Deserialize, Clone, Copy, PartialEq, Eq)]
+enum Format {
+  Raw,
+  TarGz,
+  TarBz2,
+  TarLz4,
+}
+
+impl Default for Format {
+  fn default() -> Self {
+    Format::Raw
+  }
+}
+
+// 把有界通道的接收端包装成 `Read`，供（运行在专用线程上的）解码器顺序消费
+struct ChannelReader {
+  rx: tokio::sync::mpsc::Receiver<Vec<u8>>,
+  buf: Vec<u8>,
+  pos: usize,
+}
+
+impl std::io::Read for ChannelReader {
+  fn read(&mut self, out: &mut [u8]) -> std::io::Result<usize> {
+    // 当前块耗尽则阻塞等待下一块；发送端关闭即视为 EOF
+    while self.pos >= self.buf.len() {
+      match self.rx.blocking_recv() {
+        Some(chunk) => {
+          self.buf = chunk;
+          self.pos = 0;
+        }
+        None => return Ok(0),
+      }
+    }
+    let n = std::cmp::min(out.len(), self.buf.len() - self.pos);
+    out[..n].copy_from_slice(&self.buf[self.pos..self.pos + n]);
+    self.pos += n;
+    Ok(n)
+  }
+}
+
 #[derive(Serialize)]
 struct ProgressPayload {
   download_id: String,
@@ -66,36 +138,164 @@ struct ProgressPayload {
   percentage: f64,
 }
 
+#[derive(Serialize, Clone)]
+struct VerifyPayload {
+  download_id: String,
+  digest: String,
+}
+
+/// 重新读取产物并与 `algo:hex` 形式的期望摘要比对
+fn verify_digest(path: &str, expected: &str) -> Result<(), String> {
+  let (algo, want) = expected
+    .split_once(':')
+    .ok_or_else(|| format!("无法解析期望摘要: {}", expected))?;
+  let mut f = File::open(path).map_err(|e| e.to_string())?;
+  let mut buf = Vec::new();
+  f.read_to_end(&mut buf).map_err(|e| e.to_string())?;
+  let actual = match algo.to_ascii_lowercase().as_str() {
+    "sha256" => {
+      let mut hasher = Sha256::new();
+      hasher.update(&buf);
+      format!("{:x}", hasher.finalize())
+    }
+    "md5" => format!("{:x}", md5::compute(&buf)),
+    other => return Err(format!("不支持的摘要算法: {}", other)),
+  };
+  if !actual.eq_ignore_ascii_case(want) {
+    return Err(format!("校验失败: 期望 {}:{}, 实际 {}:{}", algo, want, algo, actual));
+  }
+  Ok(())
+}
+
+#[derive(Serialize, Clone)]
+struct LifecyclePayload {
+  download_id: String,
+  // started / resumed / finished / renamed
+  event: String,
+  filename: String,
+}
+
+/// 从 `Content-Disposition` 头里解析 `filename="..."`
+fn filename_from_disposition(value: &str) -> Option<String> {
+  value.split(';').find_map(|part| {
+    let part = part.trim();
+    part.strip_prefix("filename=").map(|f| f.trim_matches('"').to_string())
+  })
+}
+
 /// 开始或恢复下载
 #[command]
-async fn download(app: AppHandle, id: String, url: String, output: String) -> Result<(), String> {
+async fn download(app: AppHandle, id: String, url: String, output: String, format: Option<Format>, http2: Option<bool>, expected: Option<String>, max_per_host: Option<usize>) -> Result<(), String> {
+  // 可选参数回退到默认值，保证旧调用方不传这些 arg 也能工作
+  let format = format.unwrap_or_default();
+  let http2 = http2.unwrap_or(false);
+
   // 载入或初始化状态
-  let mut state = DownloadState::load(&output).unwrap_or_else(|| {
-    // HEAD 获取文件大小
-    let rt = tokio::runtime::Runtime::new().unwrap();
-    let total = rt.block_on(async {
-      let h = reqwest::Client::new()
-        .head(&url)
-        .send().await.map_err(|e| e.to_string())?;
-      Ok::<u64, String>(
-        h.headers()
+  let resumed = DownloadState::load(&output).is_some();
+  let state = match DownloadState::load(&output) {
+    Some(s) => s,
+    None => {
+      // HEAD 探测：取文件大小、Accept-Ranges，以及 Content-Disposition 文件名
+      let rt = tokio::runtime::Runtime::new().unwrap();
+      let (supports_ranges, total, disposition) = rt.block_on(async {
+        let h = reqwest::Client::new()
+          .head(&url)
+          .send().await.map_err(|e| e.to_string())?;
+        let headers = h.headers();
+        // 缺失或 `none` 都视为不支持 Range
+        let supports_ranges = headers
+          .get(reqwest::header::ACCEPT_RANGES)
+          .and_then(|v| v.to_str().ok())
+          .map(|v| !v.eq_ignore_ascii_case("none"))
+          .unwrap_or(false);
+        // Content-Length 可能缺失（如分块传输），此时返回 None
+        let total = headers
           .get(reqwest::header::CONTENT_LENGTH)
-          .ok_or("无 Content-Length")?
-          .to_str().unwrap()
-          .parse().unwrap(),
-      )
-    }).unwrap();
-
-    let concurrency = 8;
-    let part = total / concurrency as u64;
-    let mut segments = Vec::new();
-    for i in 0..concurrency {
-      let start = i as u64 * part;
-      let end = if i==concurrency-1 { total-1 } else { (i as u64+1)*part -1 };
-      segments.push(SegmentState { start, end, downloaded: 0 });
+          .and_then(|v| v.to_str().ok())
+          .and_then(|v| v.parse::<u64>().ok());
+        let disposition = headers
+          .get(reqwest::header::CONTENT_DISPOSITION)
+          .and_then(|v| v.to_str().ok())
+          .and_then(filename_from_disposition);
+        Ok::<(bool, Option<u64>, Option<String>), String>((supports_ranges, total, disposition))
+      })?;
+
+      // 调用方传入目录时，用 Content-Disposition 文件名（回退到 URL 末段）补全路径。
+      // 仅对 Raw 下载生效：解压模式下目录就是解包目标，不能改写成文件路径。
+      let output = {
+        let p = Path::new(&output);
+        if p.is_dir() && format == Format::Raw {
+          let name = disposition.unwrap_or_else(|| {
+            url.rsplit('/').find(|s| !s.is_empty()).unwrap_or("download").to_string()
+          });
+          p.join(name).to_string_lossy().into_owned()
+        } else {
+          output.clone()
+        }
+      };
+
+      // 仅当服务器明确支持 Range 且给出长度时才走并发分片路径
+      match (supports_ranges, total) {
+        (true, Some(total)) => {
+          let concurrency = 8;
+          let part = total / concurrency as u64;
+          let mut segments = Vec::new();
+          for i in 0..concurrency {
+            let start = i as u64 * part;
+            let end = if i==concurrency-1 { total-1 } else { (i as u64+1)*part -1 };
+            segments.push(SegmentState { start, end, downloaded: 0 });
+          }
+          DownloadState { id: id.clone(), url: url.clone(), output: output.clone(), total_size: total, concurrency, use_ranges: true, segments }
+        }
+        // 不支持 Range 或长度未知：单分片顺序下载整条响应体
+        _ => {
+          let total = total.unwrap_or(0);
+          let end = total.saturating_sub(1);
+          let segments = vec![SegmentState { start: 0, end, downloaded: 0 }];
+          DownloadState { id: id.clone(), url: url.clone(), output: output.clone(), total_size: total, concurrency: 1, use_ranges: false, segments }
+        }
+      }
+    }
+  };
+  let mut state = state;
+
+  // 解压模式必须严格顺序消费字节，强制单分片、不使用 Range
+  let extract = format != Format::Raw;
+  if extract {
+    state.use_ranges = false;
+    if state.segments.len() != 1 {
+      let end = state.total_size.saturating_sub(1);
+      state.segments = vec![SegmentState { start: 0, end, downloaded: 0 }];
+      state.concurrency = 1;
     }
-    DownloadState { id: id.clone(), url: url.clone(), output: output.clone(), total_size: total, concurrency, segments }
-  });
+  }
+
+  // 非 Range 传输不能续传：不发 Range 的 GET 总是从头返回整条响应体，
+  // 若沿用已保存的 downloaded 偏移写入会导致文件错位损坏，故重置进度从头重来。
+  if !state.use_ranges {
+    for seg in state.segments.iter_mut() {
+      seg.downloaded = 0;
+    }
+  }
+
+  // 最终产物与下载时使用的临时文件（Raw 模式先写 .part 再原子 rename）
+  let final_output = state.output.clone();
+  let temp_output = format!("{}.part", final_output);
+  let final_name = Path::new(&final_output)
+    .file_name()
+    .map(|n| n.to_string_lossy().into_owned())
+    .unwrap_or_else(|| final_output.clone());
+
+  // 生命周期事件：与 DOWNLOAD_PROGRESS 对应，方便前端响应关键节点
+  let emit_lifecycle = |event: &str| {
+    app
+      .emit_all(
+        "DOWNLOAD_LIFECYCLE",
+        LifecyclePayload { download_id: id.clone(), event: event.to_string(), filename: final_name.clone() },
+      )
+      .ok();
+  };
+  emit_lifecycle(if resumed { "resumed" } else { "started" });
 
   // 生成取消 token 并存储
   let token = CancellationToken::new();
@@ -105,7 +305,14 @@ async fn download(app: AppHandle, id: String, url: String, output: String) -> Re
   let retry_mw = RetryTransientMiddleware::new_with_policy(
     ExponentialBackoff::builder().build_with_max_retries(3)
   );
-  let client = reqwest::Client::builder()
+  // 开启 http2 时通过 ALPN 协商 h2，把所有 range 请求复用到单条连接上；
+  // 若服务器不支持 h2，ALPN 会自动降级到 HTTP/1.1，无需调用方干预。
+  // 关闭开关时强制 HTTP/1.1，保持原有多连接行为。
+  let mut builder = reqwest::Client::builder();
+  if !http2 {
+    builder = builder.http1_only();
+  }
+  let client = builder
     .with(retry_mw)
     .build().map_err(|e| e.to_string())?;
 
@@ -113,42 +320,109 @@ async fn download(app: AppHandle, id: String, url: String, output: String) -> Re
   let start_time = Instant::now();
   let mut last_emit = Instant::now();
 
+  // 解析 host 以便按来源限流
+  let host = reqwest::Url::parse(&state.url)
+    .ok()
+    .and_then(|u| u.host_str().map(|h| h.to_string()))
+    .unwrap_or_else(|| state.url.clone());
+
+  // 解压模式：有界通道把收到的字节推给专门的解码线程，在线解包到输出目录
+  let (tx, decode_handle) = if extract {
+    let (tx, rx) = tokio::sync::mpsc::channel::<Vec<u8>>(64);
+    let reader = ChannelReader { rx, buf: Vec::new(), pos: 0 };
+    let dest = state.output.clone();
+    let fmt = format;
+    let handle = std::thread::spawn(move || -> Result<(), String> {
+      let dest = Path::new(&dest);
+      std::fs::create_dir_all(dest).map_err(|e| e.to_string())?;
+      match fmt {
+        Format::TarGz => {
+          let mut ar = tar::Archive::new(flate2::read::GzDecoder::new(reader));
+          ar.unpack(dest).map_err(|e| e.to_string())?;
+        }
+        Format::TarBz2 => {
+          let mut ar = tar::Archive::new(bzip2::read::BzDecoder::new(reader));
+          ar.unpack(dest).map_err(|e| e.to_string())?;
+        }
+        Format::TarLz4 => {
+          let dec = lz4::Decoder::new(reader).map_err(|e| e.to_string())?;
+          let mut ar = tar::Archive::new(dec);
+          ar.unpack(dest).map_err(|e| e.to_string())?;
+        }
+        Format::Raw => unreachable!(),
+      }
+      Ok(())
+    });
+    (Some(tx), Some(handle))
+  } else {
+    (None, None)
+  };
+
   // 并发下载各分片
+  let use_ranges = state.use_ranges;
   let mut handles = vec![];
   for seg in state.segments.iter_mut() {
     let url = state.url.clone();
-    let out = state.output.clone();
+    // Raw 模式写入临时 .part 文件，完成校验后再 rename 到最终路径
+    let out = temp_output.clone();
     let seg_copy = seg.clone();
     let client = client.clone();
     let cancel = token.clone();
+    let sem = host_semaphore(&host, max_per_host.unwrap_or(DEFAULT_MAX_REQUESTS_PER_HOST));
+    let tx = tx.clone();
 
     handles.push(task::spawn(async move {
+      // 取得该 host 的许可，整个传输期间持有，限制单一来源的总连接数
+      let _permit = sem.acquire_owned().await.map_err(|e| e.to_string())?;
       let mut downloaded = seg_copy.downloaded;
-      let resp = client.get(&url)
-        .header(reqwest::header::RANGE, format!("bytes={}-{}", seg_copy.start + downloaded, seg_copy.end))
-        .send().await.map_err(|e| e.to_string())?;
+      let mut req = client.get(&url);
+      // 单流模式不发送 Range，整条响应体顺序写入
+      if use_ranges {
+        req = req.header(reqwest::header::RANGE, format!("bytes={}-{}", seg_copy.start + downloaded, seg_copy.end));
+      }
+      let resp = req.send().await.map_err(|e| e.to_string())?;
       let mut stream = resp.bytes_stream();
 
-      let mut f = OpenOptions::new()
-        .create(true)
-        .write(true)
-        .open(&out)
-        .map_err(|e| e.to_string())?;
-      f.seek(SeekFrom::Start(seg_copy.start + downloaded)).unwrap();
-
-      while let Some(chunk) = select! {
-        _ = cancel.cancelled() => break None,
-        x = stream.next() => Some(x)
-      } {
-        let buf = chunk.map_err(|e| e.to_string())?;
-        f.write_all(&buf).map_err(|e| e.to_string())?;
-        downloaded += buf.len() as u64;
+      if let Some(tx) = tx {
+        // 解压模式：把原始字节顺序推入解码通道，进度按已消费的压缩字节计
+        while let Some(chunk) = select! {
+          _ = cancel.cancelled() => break None,
+          x = stream.next() => Some(x)
+        } {
+          let buf = chunk.map_err(|e| e.to_string())?;
+          downloaded += buf.len() as u64;
+          // await 异步发送，背压时让出 worker 线程而非阻塞整个 runtime
+          tx.send(buf.to_vec()).await.map_err(|e| e.to_string())?;
+        }
+        // 关闭本端 sender，任务结束时通知解码线程 EOF
+        drop(tx);
+      } else {
+        let mut f = OpenOptions::new()
+          .create(true)
+          .write(true)
+          // 非 Range 的单流下载从头重写，清掉上一轮可能残留的字节
+          .truncate(!use_ranges)
+          .open(&out)
+          .map_err(|e| e.to_string())?;
+        f.seek(SeekFrom::Start(seg_copy.start + downloaded)).unwrap();
+
+        while let Some(chunk) = select! {
+          _ = cancel.cancelled() => break None,
+          x = stream.next() => Some(x)
+        } {
+          let buf = chunk.map_err(|e| e.to_string())?;
+          f.write_all(&buf).map_err(|e| e.to_string())?;
+          downloaded += buf.len() as u64;
+        }
       }
       Ok::<u64, String>(downloaded)
     }));
   }
+  // 丢弃外层 sender，确保全部下载任务结束后解码线程能收到 EOF
+  drop(tx);
 
   // 监控并推送进度
+  let mut finished = vec![false; handles.len()];
   loop {
     if token.is_cancelled() {
       state.save().map_err(|e| e.to_string())?;
@@ -156,12 +430,28 @@ async fn download(app: AppHandle, id: String, url: String, output: String) -> Re
     }
     let mut total_dl = 0;
     for (i, h) in handles.iter_mut().enumerate() {
-      if let Ok(Some(Ok(d))) = h.now_or_never() {
-        state.segments[i].downloaded = d;
+      if !finished[i] {
+        if let Some(res) = h.now_or_never() {
+          finished[i] = true;
+          match res {
+            Ok(Ok(d)) => state.segments[i].downloaded = d,
+            // 分片任务失败（网络 / 写盘 / panic）：保留 .state 以便续传并立即上报错误，
+            // 不能把失败折叠进 "finished" 而误判整体完成
+            Ok(Err(e)) => {
+              state.save().map_err(|e| e.to_string())?;
+              return Err(e);
+            }
+            Err(e) => {
+              state.save().map_err(|e| e.to_string())?;
+              return Err(e.to_string());
+            }
+          }
+        }
       }
       total_dl += state.segments[i].downloaded;
     }
-    let pct = total_dl as f64 * 100.0 / state.total_size as f64;
+    // 长度未知时用 0，避免除零
+    let pct = if state.total_size > 0 { total_dl as f64 * 100.0 / state.total_size as f64 } else { 0.0 };
     let elapsed = start_time.elapsed().as_secs_f64();
     let rate = total_dl as f64 / elapsed;
 
@@ -175,12 +465,52 @@ async fn download(app: AppHandle, id: String, url: String, output: String) -> Re
       app.emit_all("DOWNLOAD_PROGRESS", payload).ok();
       last_emit = Instant::now();
     }
-    if total_dl >= state.total_size {
+    // 已知长度按字节判完成；长度未知时按所有任务结束判完成
+    let all_finished = finished.iter().all(|&f| f);
+    if state.total_size > 0 {
+      if total_dl >= state.total_size {
+        break;
+      }
+      if all_finished {
+        // 所有任务已结束但字节不足（如连接提前关闭）：保留 .part 与 .state，不当作完成
+        state.save().map_err(|e| e.to_string())?;
+        return Err(format!("下载不完整: {}/{}", total_dl, state.total_size));
+      }
+    } else if all_finished {
       break;
     }
     tokio::time::sleep(std::time::Duration::from_millis(100)).await;
   }
 
+  // 解压模式：等待解码线程把剩余缓冲解包完毕并上报其错误
+  if let Some(handle) = decode_handle {
+    handle.join().map_err(|_| "解码线程异常退出".to_string())??;
+  }
+  emit_lifecycle("finished");
+
+  // 解压模式直接解包到目录，没有临时产物；Raw 模式校验并 rename 临时文件
+  if !extract {
+    // 完整性校验：在 rename 前对临时文件计算摘要
+    if let Some(expected) = expected.as_deref() {
+      if let Err(e) = verify_digest(&temp_output, expected) {
+        // 校验失败说明 .part 内容已损坏：重置各分片进度并丢弃 .part，
+        // 否则续传会以空 Range 重复校验同一份坏文件、永远失败。
+        for seg in state.segments.iter_mut() {
+          seg.downloaded = 0;
+        }
+        let _ = std::fs::remove_file(&temp_output);
+        let _ = state.save();
+        return Err(e);
+      }
+      app
+        .emit_all("DOWNLOAD_VERIFIED", VerifyPayload { download_id: id.clone(), digest: expected.to_string() })
+        .ok();
+    }
+    // 校验通过后原子地落到最终路径，部分完成的文件始终带 .part 后缀
+    std::fs::rename(&temp_output, &final_output).map_err(|e| e.to_string())?;
+    emit_lifecycle("renamed");
+  }
+
   // 完成后删除 .state
   let _ = std::fs::remove_file(state.state_file());
   Ok(())